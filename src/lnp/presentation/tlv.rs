@@ -14,14 +14,26 @@
 use core::any::Any;
 use std::collections::{btree_map, BTreeMap};
 use std::io;
+use std::ops::RangeInclusive;
 use std::sync::Arc;
 
-use lightning::util::ser::{BigSize, Readable};
+use lightning::util::ser::{BigSize, Readable, Writeable};
 
 use super::{Error, Unmarshall, UnmarshallFn};
 use crate::lnp::LNP_MSG_MAX_LEN;
 use lightning::ln::msgs::DecodeError;
 
+/// Counterpart to [`Unmarshall`]: serializes a value of type `T` to `writer`.
+pub trait Marshall<T, W> {
+    type Error;
+
+    fn marshall(&self, value: &T, writer: &mut W) -> Result<(), Self::Error>;
+}
+
+/// Serializes the `Arc<dyn Any>` registered under a `TypeId` into a writer.
+/// Mirrors [`UnmarshallFn`], the decode-side equivalent.
+pub type MarshallFn<Error> = fn(&Arc<dyn Any>, &mut dyn io::Write) -> Result<(), Error>;
+
 wrapper!(
     TypeId,
     u64,
@@ -47,6 +59,20 @@ impl TypeId {
     }
 }
 
+/// A strongly-typed TLV record associated with a fixed [`TypeId`]. Types
+/// implementing this trait can be registered with an [`Unmarshaller`] via
+/// [`Unmarshaller::register`] and retrieved back out of a `Stream` with
+/// `stream.get::<T>()`, or registered with a [`Marshaller`] via
+/// [`Marshaller::register`] to encode them back out, without the `Any`
+/// downcast gymnastics that come with hand-wired parsers.
+pub trait TlvRecord: Any + Sized {
+    const TYPE_ID: TypeId;
+
+    fn read<R: io::Read>(reader: &mut R) -> Result<Self, Error>;
+
+    fn write<W: io::Write + ?Sized>(&self, writer: &mut W) -> Result<(), Error>;
+}
+
 #[derive(Debug, Display, Default)]
 #[display_from(Debug)]
 pub struct Stream(BTreeMap<TypeId, Arc<dyn Any>>);
@@ -56,8 +82,19 @@ impl Stream {
         Self::default()
     }
 
-    pub fn get<T: Any>(&self, type_id: &TypeId) -> Option<&T> {
-        self.0.get(type_id).and_then(|v| v.downcast_ref::<T>())
+    pub fn get<T: TlvRecord>(&self) -> Option<&T> {
+        self.0.get(&T::TYPE_ID).and_then(|v| v.downcast_ref::<T>())
+    }
+
+    /// Iterates over records that were not registered with a known
+    /// [`TlvRecord`] type: the unknown **odd**-type records upstream users
+    /// may still know how to interpret. BOLT-1 requires unknown **even**
+    /// types to fail parsing outright, so they never make it into a
+    /// `Stream`.
+    pub fn unknown_records(&self) -> impl Iterator<Item = (&TypeId, &RawRecord)> {
+        self.0
+            .iter()
+            .filter_map(|(type_id, rec)| rec.downcast_ref::<RawRecord>().map(|raw| (type_id, raw)))
     }
 
     pub fn insert(&mut self, type_id: TypeId, value: Arc<dyn Any>) -> bool {
@@ -71,13 +108,81 @@ impl Stream {
     pub fn entry(&mut self, type_id: TypeId) -> btree_map::Entry<TypeId, Arc<dyn Any>> {
         self.0.entry(type_id)
     }
+
+    pub fn iter(&self) -> btree_map::Iter<TypeId, Arc<dyn Any>> {
+        self.0.iter()
+    }
+}
+
+/// Parser registered against a known `TypeId`. Unlike the generic
+/// [`UnmarshallFn`], this always sees a reader limited to exactly the
+/// record's declared length, so individual parsers no longer need to police
+/// over- or under-reads themselves.
+type KnownRecordFn<R, Error> = fn(&mut LengthLimitedReader<R>) -> Result<Arc<dyn Any>, Error>;
+
+/// Reader adapter that exposes only the next `limit` bytes of the underlying
+/// reader and records how many of them were actually consumed, so callers
+/// can confirm a record's parser read exactly its declared length (BOLT-1:
+/// "if length is not exactly equal to that required for the known encoding
+/// for type, MUST fail to parse the tlv_stream").
+struct LengthLimitedReader<'a, R> {
+    inner: &'a mut R,
+    remaining: u64,
+    bytes_read: u64,
+}
+
+impl<'a, R> LengthLimitedReader<'a, R> {
+    fn new(inner: &'a mut R, limit: u64) -> Self {
+        Self {
+            inner,
+            remaining: limit,
+            bytes_read: 0,
+        }
+    }
+}
+
+impl<'a, R: io::Read> io::Read for LengthLimitedReader<'a, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let max = buf.len().min(self.remaining as usize);
+        let n = self.inner.read(&mut buf[..max])?;
+        self.remaining -= n as u64;
+        self.bytes_read += n as u64;
+        Ok(n)
+    }
+}
+
+/// Reader adapter used while reading a record's leading `type` field that
+/// records how many bytes have been consumed since the last record
+/// boundary. This lets a `ShortRead` be told apart from true end-of-stream
+/// (zero bytes consumed) from a truncation partway through the `BigSize`
+/// (some bytes consumed), which BOLT-1's TLV test vectors distinguish.
+struct BoundaryReader<'a, R> {
+    inner: &'a mut R,
+    bytes_read: u64,
+}
+
+impl<'a, R> BoundaryReader<'a, R> {
+    fn new(inner: &'a mut R) -> Self {
+        Self {
+            inner,
+            bytes_read: 0,
+        }
+    }
+}
+
+impl<'a, R: io::Read> io::Read for BoundaryReader<'a, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.bytes_read += n as u64;
+        Ok(n)
+    }
 }
 
 pub struct Unmarshaller<R>
 where
     R: io::Read,
 {
-    known_types: BTreeMap<TypeId, UnmarshallFn<R, Error>>,
+    known_types: BTreeMap<TypeId, KnownRecordFn<R, Error>>,
     raw_parser: UnmarshallFn<R, Error>,
 }
 
@@ -91,58 +196,27 @@ where
         let mut tlv = Stream::new();
         let mut prev_type_id = TypeId(0);
         loop {
-            match BigSize::read(&mut reader).map(|big_size| TypeId(big_size.0)) {
+            let mut boundary = BoundaryReader::new(&mut reader);
+            match BigSize::read(&mut boundary).map(|big_size| TypeId(big_size.0)) {
                 // if zero bytes remain before parsing a type
                 // MUST stop parsing the tlv_stream
-                Err(DecodeError::ShortRead) => break Ok(tlv),
+                Err(DecodeError::ShortRead) if boundary.bytes_read == 0 => break Ok(tlv),
+
+                // a truncation partway through a type's `BigSize` is not a
+                // clean end of stream but a hard parse failure
+                Err(DecodeError::ShortRead) => break Err(Error::TlvRecordInvalidLen),
 
                 // The following rule is handled by BigSize type:
                 // if a type or length is not minimally encoded
                 // MUST fail to parse the tlv_stream.
                 Err(err) => break Err(Error::from(err)),
 
-                // if decoded types are not monotonically-increasing
-                // MUST fail to parse the tlv_stream.
-                Ok(type_id) if type_id > prev_type_id => break Err(Error::TlvStreamWrongOrder),
-
-                // if decoded `type`s are not strictly-increasing
-                // (including situations when two or more occurrences of the \
-                // same `type` are met)
-                // MUST fail to parse the tlv_stream.
-                Ok(type_id) if tlv.contains_key(&type_id) => {
-                    break Err(Error::TlvStreamDuplicateItem)
-                }
-
                 Ok(type_id) => {
-                    let rec = if let Some(parser) = self.known_types.get(&type_id) {
-                        // if type is known:
-                        // MUST decode the next length bytes using the known
-                        // encoding for type.
-                        // The rest of rules MUST be supported by the parser:
-                        // - if length is not exactly equal to that required for
-                        //   the known encoding for type
-                        //   MUST fail to parse the tlv_stream.
-                        // - if variable-length fields within the known encoding
-                        //   for type are not minimal
-                        //   MUST fail to parse the tlv_stream.
-                        parser(&mut reader)?
-                    }
-                    // otherwise, if type is unknown:
-                    // if type is even:
-                    // MUST fail to parse the tlv_stream.
-                    else if type_id.is_even() {
-                        break Err(Error::TlvRecordEvenType);
+                    if let Err(err) =
+                        self.read_record(&mut reader, &mut tlv, &mut prev_type_id, type_id)
+                    {
+                        break Err(err);
                     }
-                    // otherwise, if type is odd:
-                    // MUST discard the next length bytes.
-                    else {
-                        // Here we are actually not discarding the bytes but
-                        // rather store them for an upstream users of the
-                        // library which may know the meaning of the bytes
-                        (self.raw_parser)(&mut reader)?
-                    };
-                    tlv.insert(type_id, rec);
-                    prev_type_id = type_id;
                 }
             }
         }
@@ -160,6 +234,78 @@ where
         }
     }
 
+    /// Registers `T` as the known parser for its [`TlvRecord::TYPE_ID`],
+    /// so that it is decoded automatically and retrievable with
+    /// `stream.get::<T>()`.
+    pub fn register<T: TlvRecord>(mut self) -> Self {
+        self.known_types.insert(T::TYPE_ID, Self::wrap::<T>);
+        self
+    }
+
+    fn wrap<T: TlvRecord>(reader: &mut LengthLimitedReader<R>) -> Result<Arc<dyn Any>, Error> {
+        T::read(reader).map(|record| Arc::new(record) as Arc<dyn Any>)
+    }
+
+    /// Validates `type_id`'s ordering against `prev_type_id` and `tlv`, then
+    /// decodes its body from `reader` and inserts the record into `tlv`.
+    /// Shared by [`Unmarshall::unmarshall`] and [`Unmarshaller::unmarshall_range`],
+    /// which only differ in when they decide to stop reading records, not in
+    /// how an individual record is validated and parsed.
+    fn read_record(
+        &self,
+        reader: &mut R,
+        tlv: &mut Stream,
+        prev_type_id: &mut TypeId,
+        type_id: TypeId,
+    ) -> Result<(), Error> {
+        // if decoded types are not monotonically-increasing
+        // MUST fail to parse the tlv_stream.
+        if type_id < *prev_type_id {
+            return Err(Error::TlvStreamWrongOrder);
+        }
+
+        // if decoded `type`s are not strictly-increasing
+        // (including situations when two or more occurrences of the \
+        // same `type` are met)
+        // MUST fail to parse the tlv_stream.
+        if tlv.contains_key(&type_id) {
+            return Err(Error::TlvStreamDuplicateItem);
+        }
+
+        let rec = if let Some(parser) = self.known_types.get(&type_id) {
+            // if type is known:
+            // MUST decode the next length bytes using the known
+            // encoding for type.
+            // - if length is not exactly equal to that required for
+            //   the known encoding for type
+            //   MUST fail to parse the tlv_stream.
+            let length = BigSize::read(&mut *reader).map_err(Error::from)?.0;
+            let mut limited = LengthLimitedReader::new(&mut *reader, length);
+            let rec = parser(&mut limited)?;
+            if limited.bytes_read != length {
+                return Err(Error::TlvRecordInvalidLen);
+            }
+            rec
+        }
+        // otherwise, if type is unknown:
+        // if type is even:
+        // MUST fail to parse the tlv_stream.
+        else if type_id.is_even() {
+            return Err(Error::TlvRecordEvenType);
+        }
+        // otherwise, if type is odd:
+        // MUST discard the next length bytes.
+        else {
+            // Here we are actually not discarding the bytes but
+            // rather store them for an upstream users of the
+            // library which may know the meaning of the bytes
+            (self.raw_parser)(&mut *reader)?
+        };
+        tlv.insert(type_id, rec);
+        *prev_type_id = type_id;
+        Ok(())
+    }
+
     fn raw_parser(mut reader: &mut R) -> Result<Arc<dyn Any>, Error> {
         let len = BigSize::read(&mut reader)?.0 as usize;
 
@@ -185,3 +331,290 @@ where
         Ok(Arc::new(rec))
     }
 }
+
+impl<R> Unmarshaller<R>
+where
+    R: io::Read + io::Seek,
+{
+    /// Unmarshalls only the records whose `type` falls within `range`,
+    /// stopping as soon as a type above the range is encountered and
+    /// rewinding the reader back to right before that type's `BigSize`.
+    ///
+    /// This lets several sub-streams that each own a disjoint range of TLV
+    /// types (as BOLT-12 messages do for `offer` / `invoice_request` /
+    /// `invoice`) be unmarshalled out of one shared reader: call
+    /// `unmarshall_range(&mut reader, TypeId(0)..=TypeId(159))`, then pass
+    /// the same `&mut reader` into the next range's call.
+    pub fn unmarshall_range(
+        &self,
+        reader: &mut R,
+        range: RangeInclusive<TypeId>,
+    ) -> Result<Stream, Error> {
+        let mut tlv = Stream::new();
+        let mut prev_type_id = TypeId(0);
+        loop {
+            let mut boundary = BoundaryReader::new(&mut *reader);
+            match BigSize::read(&mut boundary).map(|big_size| TypeId(big_size.0)) {
+                // if zero bytes remain before parsing a type
+                // MUST stop parsing the tlv_stream
+                Err(DecodeError::ShortRead) if boundary.bytes_read == 0 => break Ok(tlv),
+
+                // a truncation partway through a type's `BigSize` is not a
+                // clean end of stream but a hard parse failure
+                Err(DecodeError::ShortRead) => break Err(Error::TlvRecordInvalidLen),
+
+                Err(err) => break Err(Error::from(err)),
+
+                // a type below the requested range belongs to an earlier
+                // sub-stream that should already have consumed it
+                Ok(type_id) if type_id < *range.start() => {
+                    break Err(Error::TlvStreamWrongOrder)
+                }
+
+                // a type above the requested range belongs to a later
+                // sub-stream: rewind past the `BigSize` we just consumed so
+                // the caller can hand the same reader to that range's
+                // unmarshaller
+                Ok(type_id) if type_id > *range.end() => {
+                    // read out of `boundary` before touching `reader` again:
+                    // `boundary` still holds it mutably borrowed at this point
+                    let consumed = boundary.bytes_read as i64;
+                    reader
+                        .seek(io::SeekFrom::Current(-consumed))
+                        .map_err(|_| Error::TlvRecordInvalidLen)?;
+                    break Ok(tlv);
+                }
+
+                Ok(type_id) => {
+                    if let Err(err) =
+                        self.read_record(&mut *reader, &mut tlv, &mut prev_type_id, type_id)
+                    {
+                        break Err(err);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// A `Write` sink that only accumulates the number of bytes that would have
+/// been written, used to learn a record's encoded length before writing its
+/// `BigSize` length prefix.
+struct LengthCounter(u64);
+
+impl io::Write for LengthCounter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0 += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+pub struct Marshaller<W>
+where
+    W: io::Write,
+{
+    known_types: BTreeMap<TypeId, MarshallFn<Error>>,
+    _writer: std::marker::PhantomData<W>,
+}
+
+impl<W> Marshall<Stream, W> for Marshaller<W>
+where
+    W: io::Write,
+{
+    type Error = Error;
+
+    fn marshall(&self, stream: &Stream, writer: &mut W) -> Result<(), Self::Error> {
+        // the `BTreeMap` backing `Stream` already yields records in
+        // ascending `TypeId` order, so a canonical stream falls out for free
+        for (type_id, rec) in stream.iter() {
+            BigSize(type_id.0)
+                .write(&mut *writer)
+                .map_err(|_| Error::TlvRecordInvalidLen)?;
+
+            // run the record's writer against a length-counting sink first so
+            // we can write the real length prefix without double-encoding
+            let mut counter = LengthCounter(0);
+            self.write_record(*type_id, rec, &mut counter)?;
+            BigSize(counter.0)
+                .write(&mut *writer)
+                .map_err(|_| Error::TlvRecordInvalidLen)?;
+
+            self.write_record(*type_id, rec, &mut *writer)?;
+        }
+        Ok(())
+    }
+}
+
+impl<W> Marshaller<W>
+where
+    W: io::Write,
+{
+    pub fn new() -> Self {
+        Self {
+            known_types: BTreeMap::new(),
+            _writer: std::marker::PhantomData,
+        }
+    }
+
+    /// Registers `T` as the known writer for its [`TlvRecord::TYPE_ID`], so
+    /// records of that type are encoded via [`TlvRecord::write`] instead of
+    /// falling through to the `RawRecord` path.
+    pub fn register<T: TlvRecord>(mut self) -> Self {
+        self.known_types.insert(T::TYPE_ID, Self::wrap::<T>);
+        self
+    }
+
+    fn wrap<T: TlvRecord>(rec: &Arc<dyn Any>, writer: &mut dyn io::Write) -> Result<(), Error> {
+        rec.downcast_ref::<T>()
+            .ok_or(Error::TlvRecordInvalidLen)?
+            .write(writer)
+    }
+
+    fn write_record(
+        &self,
+        type_id: TypeId,
+        rec: &Arc<dyn Any>,
+        writer: &mut dyn io::Write,
+    ) -> Result<(), Error> {
+        if let Some(marshall_fn) = self.known_types.get(&type_id) {
+            marshall_fn(rec, writer)
+        } else if let Some(raw) = rec.downcast_ref::<RawRecord>() {
+            // `RawRecord`s (unknown odd-type records) round-trip verbatim
+            writer
+                .write_all(&raw.0)
+                .map_err(|_| Error::TlvRecordInvalidLen)
+        } else {
+            Err(Error::TlvRecordInvalidLen)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    struct Rec64(u64);
+
+    impl TlvRecord for Rec64 {
+        const TYPE_ID: TypeId = TypeId(1);
+
+        fn read<R: io::Read>(reader: &mut R) -> Result<Self, Error> {
+            let mut buf = [0u8; 8];
+            reader
+                .read_exact(&mut buf)
+                .map_err(|_| Error::TlvRecordInvalidLen)?;
+            Ok(Rec64(u64::from_be_bytes(buf)))
+        }
+
+        fn write<W: io::Write + ?Sized>(&self, writer: &mut W) -> Result<(), Error> {
+            writer
+                .write_all(&self.0.to_be_bytes())
+                .map_err(|_| Error::TlvRecordInvalidLen)
+        }
+    }
+
+    fn push_record(bytes: &mut Vec<u8>, type_id: u64, body: &[u8]) {
+        BigSize(type_id).write(bytes).unwrap();
+        BigSize(body.len() as u64).write(bytes).unwrap();
+        bytes.extend_from_slice(body);
+    }
+
+    #[test]
+    fn decodes_a_stream_of_increasing_types() {
+        let mut bytes = Vec::new();
+        push_record(&mut bytes, 1, &42u64.to_be_bytes());
+        push_record(&mut bytes, 3, b"abc");
+
+        let unmarshaller = Unmarshaller::<Cursor<Vec<u8>>>::new().register::<Rec64>();
+        let stream = unmarshaller
+            .unmarshall(Cursor::new(bytes))
+            .expect("a stream of strictly-increasing types must decode");
+
+        assert_eq!(stream.get::<Rec64>().unwrap().0, 42);
+        let unknown: Vec<_> = stream.unknown_records().map(|(id, _)| id.0).collect();
+        assert_eq!(unknown, vec![3]);
+    }
+
+    #[test]
+    fn unmarshall_range_hands_the_reader_on_to_the_next_range() {
+        let mut bytes = Vec::new();
+        push_record(&mut bytes, 1, &42u64.to_be_bytes());
+        push_record(&mut bytes, 201, b"xyz");
+
+        let unmarshaller = Unmarshaller::<Cursor<Vec<u8>>>::new().register::<Rec64>();
+        let mut reader = Cursor::new(bytes);
+
+        let first = unmarshaller
+            .unmarshall_range(&mut reader, TypeId(0)..=TypeId(159))
+            .expect("the first range should decode its own record");
+        assert_eq!(first.get::<Rec64>().unwrap().0, 42);
+
+        let second = unmarshaller
+            .unmarshall_range(&mut reader, TypeId(160)..=TypeId(239))
+            .expect("the second range should pick up right where the first left off");
+        let unknown: Vec<_> = second.unknown_records().map(|(id, _)| id.0).collect();
+        assert_eq!(unknown, vec![201]);
+    }
+
+    #[test]
+    fn rejects_a_known_record_whose_length_does_not_match() {
+        let mut bytes = Vec::new();
+        // `Rec64` always expects exactly 8 bytes
+        push_record(&mut bytes, 1, &[0u8; 4]);
+
+        let unmarshaller = Unmarshaller::<Cursor<Vec<u8>>>::new().register::<Rec64>();
+        let err = unmarshaller
+            .unmarshall(Cursor::new(bytes))
+            .expect_err("a record shorter than its declared known encoding must fail");
+        assert!(matches!(err, Error::TlvRecordInvalidLen));
+    }
+
+    #[test]
+    fn clean_eof_between_records_stops_without_error() {
+        let unmarshaller = Unmarshaller::<Cursor<Vec<u8>>>::new();
+        let stream = unmarshaller
+            .unmarshall(Cursor::new(Vec::new()))
+            .expect("an empty stream is a valid, empty tlv_stream");
+        assert!(stream.unknown_records().next().is_none());
+    }
+
+    #[test]
+    fn truncation_partway_through_a_type_is_a_hard_error() {
+        // a single byte that signals a multi-byte `BigSize` type (the 0xfd
+        // prefix) but is cut off before the two value bytes that must
+        // follow it
+        let bytes = vec![0xfdu8];
+
+        let unmarshaller = Unmarshaller::<Cursor<Vec<u8>>>::new();
+        let err = unmarshaller
+            .unmarshall(Cursor::new(bytes))
+            .expect_err("truncation partway through a type's BigSize must fail");
+        assert!(matches!(err, Error::TlvRecordInvalidLen));
+    }
+
+    #[test]
+    fn marshall_and_unmarshall_round_trip() {
+        let mut bytes = Vec::new();
+        push_record(&mut bytes, 1, &42u64.to_be_bytes());
+        push_record(&mut bytes, 3, b"abc");
+
+        let unmarshaller = Unmarshaller::<Cursor<Vec<u8>>>::new().register::<Rec64>();
+        let stream = unmarshaller
+            .unmarshall(Cursor::new(bytes.clone()))
+            .expect("a stream of strictly-increasing types must decode");
+
+        let marshaller = Marshaller::<Vec<u8>>::new().register::<Rec64>();
+        let mut encoded = Vec::new();
+        marshaller
+            .marshall(&stream, &mut encoded)
+            .expect("a decoded stream must re-encode, and the caller must get the bytes back");
+
+        assert_eq!(encoded, bytes);
+    }
+}